@@ -0,0 +1,55 @@
+// Ramhorns  Copyright (C) 2019  Maciej Hirsz
+//
+// This file is part of Ramhorns. This program comes with ABSOLUTELY NO WARRANTY;
+// This is free software, and you are welcome to redistribute it under the
+// conditions of the GNU General Public License version 3.0.
+//
+// You should have received a copy of the GNU General Public License
+// along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
+
+use std::fmt;
+use arrayvec::CapacityError;
+
+/// Errors that can occur when parsing or rendering a `Template`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A tag was opened (`{{`) but never closed (`}}`).
+    UnclosedTag,
+    /// A section was opened but never closed before the end of the template.
+    UnclosedSection(String),
+    /// A closing tag didn't have a matching opening section.
+    UnopenedSection(String),
+    /// The template nests sections more than 16 levels deep.
+    TooDeeplyNested,
+    /// A named partial was used but not provided.
+    PartialNotFound(String),
+    /// A `| transform` spec didn't match any known transform.
+    UnknownTransform(String),
+    /// A tag chained more than 4 transforms.
+    TooManyTransforms,
+    /// A `field ? a:"A" | b:"B"` choice spec had a malformed arm.
+    InvalidChoice(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnclosedTag => write!(f, "Unclosed tag"),
+            Error::UnclosedSection(name) => write!(f, "Unclosed section {}", name),
+            Error::UnopenedSection(name) => write!(f, "Closing tag for unopened section {}", name),
+            Error::TooDeeplyNested => write!(f, "Sections nested too deeply"),
+            Error::PartialNotFound(name) => write!(f, "Partial not found: {}", name),
+            Error::UnknownTransform(spec) => write!(f, "Unknown transform: {}", spec),
+            Error::TooManyTransforms => write!(f, "Too many transforms chained onto one tag (max 4)"),
+            Error::InvalidChoice(arm) => write!(f, "Invalid choice arm: {}", arm),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl<T> From<CapacityError<T>> for Error {
+    fn from(_: CapacityError<T>) -> Self {
+        Error::TooDeeplyNested
+    }
+}
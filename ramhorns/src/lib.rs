@@ -0,0 +1,19 @@
+// Ramhorns  Copyright (C) 2019  Maciej Hirsz
+//
+// This file is part of Ramhorns. This program comes with ABSOLUTELY NO WARRANTY;
+// This is free software, and you are welcome to redistribute it under the
+// conditions of the GNU General Public License version 3.0.
+//
+// You should have received a copy of the GNU General Public License
+// along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
+
+//! Ramhorns is a high performance [Mustache](https://mustache.github.io/mustache.5.html)
+//! template engine implementation.
+
+mod error;
+mod template;
+mod traits;
+
+pub use error::Error;
+pub use template::Template;
+pub use traits::Partials;
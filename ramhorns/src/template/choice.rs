@@ -0,0 +1,115 @@
+// Ramhorns  Copyright (C) 2019  Maciej Hirsz
+//
+// This file is part of Ramhorns. This program comes with ABSOLUTELY NO WARRANTY;
+// This is free software, and you are welcome to redistribute it under the
+// conditions of the GNU General Public License version 3.0.
+//
+// You should have received a copy of the GNU General Public License
+// along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
+
+use crate::Error;
+
+/// The parsed payload of a `{{field ? a:"A" | b:"B" | default:"C"}}` choice
+/// tag: a list of `(match value, literal)` arms plus an optional default
+/// literal, checked in order against the rendered value of `field`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Choice<'tpl> {
+    pub arms: Vec<(&'tpl str, &'tpl str)>,
+    pub default: Option<&'tpl str>,
+}
+
+impl<'tpl> Choice<'tpl> {
+    /// Parse the `a:"A" | b:"B" | default:"C"` spec that follows the `?`
+    /// sigil of a choice tag.
+    pub fn parse(spec: &'tpl str) -> Result<Self, Error> {
+        let mut arms = Vec::new();
+        let mut default = None;
+
+        // Split on top-level `|`s only: a quoted literal (`open:"a|b"`) may
+        // itself contain the separator.
+        for arm in split_unquoted(spec, '|') {
+            let arm = arm.trim();
+            let (key, value) = arm
+                .split_once(':')
+                .ok_or_else(|| Error::InvalidChoice(arm.into()))?;
+            let key = key.trim();
+            let value = value.trim();
+
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .ok_or_else(|| Error::InvalidChoice(arm.into()))?;
+
+            if key == "default" {
+                default = Some(value);
+            } else {
+                arms.push((key, value));
+            }
+        }
+
+        Ok(Choice { arms, default })
+    }
+
+    /// Pick the literal matching `value`, falling back to the default arm
+    /// (if any), then an empty string.
+    pub fn resolve(&self, value: &str) -> &'tpl str {
+        self.arms
+            .iter()
+            .find(|(key, _)| *key == value)
+            .map(|(_, literal)| *literal)
+            .or(self.default)
+            .unwrap_or("")
+    }
+}
+
+/// Split `s` on `sep`, ignoring any `sep` that falls inside a double-quoted
+/// literal.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+
+    for (idx, c) in s.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c == sep && !in_quotes => {
+                parts.push(&s[start..idx]);
+                start = idx + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+
+    parts
+}
+
+/// Find the first run of `braces` consecutive `}` in `s`. `braces` is
+/// `closing.extras as usize` (2 for a `}}` close, 3 for `}}}`) — shared by
+/// the default, transform and choice branches of [`super::parse`] so a
+/// triple-brace tag (`{{{...}}}`) advances correctly instead of stopping at
+/// the first `}}`.
+///
+/// `quote_aware` skips any `}}` that falls inside a double-quoted choice-arm
+/// literal, so a quoted value containing `}}` can't prematurely end the tag;
+/// only the choice branch wants this; a transform or default spec may
+/// contain an unbalanced `"` of its own (e.g. a regex replacing one quote
+/// with another) and must not have it toggle an in-quotes state.
+pub(crate) fn find_tag_end(s: &str, braces: usize, quote_aware: bool) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut in_quotes = false;
+    let mut idx = 0;
+
+    while idx + braces <= bytes.len() {
+        match bytes[idx] {
+            b'"' if quote_aware => in_quotes = !in_quotes,
+            b'}' if !in_quotes && bytes[idx..idx + braces].iter().all(|&b| b == b'}') => {
+                return Some(idx)
+            }
+            _ => {}
+        }
+        idx += 1;
+    }
+
+    None
+}
@@ -0,0 +1,68 @@
+// Ramhorns  Copyright (C) 2019  Maciej Hirsz
+//
+// This file is part of Ramhorns. This program comes with ABSOLUTELY NO WARRANTY;
+// This is free software, and you are welcome to redistribute it under the
+// conditions of the GNU General Public License version 3.0.
+//
+// You should have received a copy of the GNU General Public License
+// along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
+
+use std::borrow::Cow;
+
+/// Rewrite `\{{` and `\\` escape sequences in a run of literal template text
+/// into `{{` and `\` respectively. The overwhelming majority of literal runs
+/// contain no backslash at all, so this stays zero-copy (`Cow::Borrowed`)
+/// unless an escape is actually present.
+pub(crate) fn unescape_html(html: &str) -> Cow<'_, str> {
+    if !html.as_bytes().contains(&b'\\') {
+        return Cow::Borrowed(html);
+    }
+
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(idx) = rest.find('\\') {
+        out.push_str(&rest[..idx]);
+        let tail = &rest[idx + 1..];
+
+        if let Some(tail) = tail.strip_prefix("{{") {
+            out.push_str("{{");
+            rest = tail;
+        } else if let Some(tail) = tail.strip_prefix('\\') {
+            out.push('\\');
+            rest = tail;
+        } else {
+            // Not a recognised escape sequence: keep the backslash as-is.
+            out.push('\\');
+            rest = tail;
+        }
+    }
+    out.push_str(rest);
+
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_without_backslash_is_borrowed() {
+        assert!(matches!(unescape_html("plain text"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn escaped_open_brace_becomes_literal() {
+        assert_eq!(unescape_html(r"\{{not a tag}}"), "{{not a tag}}");
+    }
+
+    #[test]
+    fn escaped_backslash_becomes_single_backslash() {
+        assert_eq!(unescape_html(r"a\\b"), r"a\b");
+    }
+
+    #[test]
+    fn unrecognised_escape_keeps_backslash() {
+        assert_eq!(unescape_html(r"a\nb"), r"a\nb");
+    }
+}
@@ -0,0 +1,180 @@
+// Ramhorns  Copyright (C) 2019  Maciej Hirsz
+//
+// This file is part of Ramhorns. This program comes with ABSOLUTELY NO WARRANTY;
+// This is free software, and you are welcome to redistribute it under the
+// conditions of the GNU General Public License version 3.0.
+//
+// You should have received a copy of the GNU General Public License
+// along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
+
+mod choice;
+mod escape;
+mod parse;
+mod scan;
+mod transform;
+
+use std::borrow::Cow;
+
+use crate::{Error, Partials};
+
+pub(crate) use choice::{find_tag_end, Choice};
+pub(crate) use escape::unescape_html;
+pub(crate) use transform::{split_chain, Transform};
+
+/// Tag of a `Block`, determining how it is interpreted at render time.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Tag {
+    Escaped,
+    Unescaped,
+    Section,
+    Inverse,
+    Closing,
+    Partial,
+    Comment,
+    /// `{{field ? a:"A" | b:"B" | default:"C"}}` — see [`Choice`].
+    Choice,
+}
+
+/// A single segment of a parsed `Template`: a run of literal HTML followed by
+/// the (optional) tag that terminates it.
+#[derive(Debug, Clone)]
+pub(crate) struct Block<'tpl> {
+    pub html: Cow<'tpl, str>,
+    pub name: &'tpl str,
+    pub hash: u64,
+    pub tag: Tag,
+    // Number of blocks that make up this section, filled in once the whole
+    // section (or tag) has been parsed.
+    pub children: u32,
+    // Value transforms (`{{name | upcase}}`) to apply before this block is
+    // rendered. Empty for the overwhelming majority of tags, which keeps
+    // them on the zero-allocation fast path.
+    pub transforms: Vec<Transform>,
+    // Literal fallback (`{{name:default}}`) emitted when `name` resolves to
+    // nothing.
+    pub default: Option<&'tpl str>,
+    // Populated only for `Tag::Choice` blocks.
+    pub choice: Option<Choice<'tpl>>,
+}
+
+impl<'tpl> Block<'tpl> {
+    // `html` takes anything convertible to `Cow<str>` so callers on the
+    // default-delimiter path can pass an already-`unescape_html`-processed
+    // `Cow`, while callers on the custom-delimiter `scan` path — which has
+    // no backslash-escape grammar of its own — can keep passing a plain
+    // `&str` and get a zero-copy `Cow::Borrowed`.
+    #[inline]
+    pub fn new(html: impl Into<Cow<'tpl, str>>, name: &'tpl str, tag: Tag) -> Self {
+        Block {
+            html: html.into(),
+            name,
+            hash: hash_name(name),
+            tag,
+            children: 0,
+            transforms: Vec::new(),
+            default: None,
+            choice: None,
+        }
+    }
+
+    #[inline]
+    pub fn nameless(html: impl Into<Cow<'tpl, str>>, tag: Tag) -> Self {
+        Block {
+            html: html.into(),
+            name: "",
+            hash: 0,
+            tag,
+            children: 0,
+            transforms: Vec::new(),
+            default: None,
+            choice: None,
+        }
+    }
+}
+
+/// Split `name:default` into the field name and its literal fallback, if any.
+#[inline]
+pub(crate) fn split_default(name: &str) -> (&str, Option<&str>) {
+    match name.find(':') {
+        Some(idx) => (&name[..idx], Some(&name[idx + 1..])),
+        None => (name, None),
+    }
+}
+
+/// Fowler-Noll-Vo hash of a field name, used to quickly compare section
+/// openings and closings without touching the underlying `&str` data.
+#[inline]
+pub(crate) fn hash_name(name: &str) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    name.bytes().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// A parsed template: its source split into `Block`s of literal text and
+/// tags, with any partials inlined.
+pub struct Template<'tpl> {
+    source: &'tpl str,
+    blocks: Vec<Block<'tpl>>,
+    capacity_hint: usize,
+}
+
+impl<'tpl> Template<'tpl> {
+    /// Parse a new `Template` out of `source`, resolving any partials via `partials`.
+    pub fn new(source: &'tpl str, partials: &mut impl Partials<'tpl>) -> Result<Self, Error> {
+        let mut tpl = Template {
+            source,
+            blocks: Vec::new(),
+            capacity_hint: 0,
+        };
+
+        tpl.parse(source, partials)?;
+
+        Ok(tpl)
+    }
+
+    /// Parse a new `Template` starting from a custom delimiter pair instead
+    /// of the default `{{`/`}}`, for output languages (LaTeX, some config
+    /// formats) that use braces themselves.
+    ///
+    /// A `{{=<% %>=}}`-style tag inside `source` can also switch delimiters
+    /// at any point, inheriting whatever pair was passed here as the
+    /// starting point. Because this has to be able to recognise whatever
+    /// pair is active at any byte offset, it always uses the hand-rolled
+    /// [`scan`] parser rather than the `logos`-based fast path `new` uses.
+    ///
+    /// Note: a partial included via `{{> name}}` is rendered with whatever
+    /// blocks were already parsed for it by `partials`; it does not retroactively
+    /// reparse the partial's own source under these delimiters.
+    pub fn with_delimiters(
+        source: &'tpl str,
+        open: &'tpl str,
+        close: &'tpl str,
+        partials: &mut impl Partials<'tpl>,
+    ) -> Result<Self, Error> {
+        let mut tpl = Template {
+            source,
+            blocks: Vec::new(),
+            capacity_hint: 0,
+        };
+
+        scan::parse_scan(&mut tpl, source, open, close, partials)?;
+
+        Ok(tpl)
+    }
+
+    /// Source this template was parsed from.
+    #[inline]
+    pub fn source(&self) -> &'tpl str {
+        self.source
+    }
+
+    /// A byte-size hint for how large a rendered buffer for this template
+    /// is likely to be, used to pre-allocate output buffers.
+    #[inline]
+    pub fn capacity_hint(&self) -> usize {
+        self.capacity_hint
+    }
+}
@@ -7,10 +7,15 @@
 // You should have received a copy of the GNU General Public License
 // along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
 
+use std::borrow::Cow;
+
 use arrayvec::ArrayVec;
 use logos::Logos;
 
-use super::{hash_name, Block, Error, Tag, Template};
+use super::{
+    find_tag_end, hash_name, scan, split_chain, split_default, unescape_html, Block, Choice,
+    Error, Tag, Template, Transform,
+};
 use crate::Partials;
 
 #[derive(Logos)]
@@ -31,8 +36,21 @@ enum Opening {
     #[token("{{!", |_| Tag::Comment)]
     Match(Tag),
 
-    #[regex(r"[^{]+", logos::skip)]
+    #[regex(r"[^{\\]+", logos::skip)]
     #[token("{", logos::skip)]
+    // `\\` (an escaped backslash) must be matched as a pair before `\{{` gets
+    // a chance to look at what follows: otherwise the second backslash of
+    // `\\{{name}}` would be treated as the start of its own `\{{` escape,
+    // swallowing the `{{name}}` tag along with it instead of leaving it to
+    // open normally after the backslash pair resolves to one literal `\`.
+    #[token(r"\\", logos::skip)]
+    // `\{{` is a literal `{{` rather than the start of a tag. `\` is
+    // excluded from the regex above so it can't be swallowed into a run of
+    // plain text before we get a chance to look at what follows it here.
+    #[token(r"\{{", logos::skip)]
+    // A backslash that isn't escaping `{{` or itself is just more literal
+    // text; `unescape_html` below resolves it once the HTML run is captured.
+    #[token("\\", logos::skip)]
     #[error]
     Err,
 }
@@ -47,6 +65,16 @@ enum Closing {
     #[token("}}}")]
     Match,
 
+    // A `|` separates a field name from a transform spec (`upcase`,
+    // `/pattern/replacement/flags`, ...), rather than opening a nested
+    // section like another whitespace-separated ident would.
+    #[token("|")]
+    Pipe,
+
+    // A `?` starts a choice spec: `{{field ? a:"A" | b:"B"}}`.
+    #[token("?")]
+    Question,
+
     #[regex(r"[^ \}]+")]
     Ident,
 
@@ -90,7 +118,12 @@ impl<'tpl> Template<'tpl> {
             // in front of the token:
             //
             // let html = &lex.before()[last..];
-            let mut html = &lex.source()[last..lex.span().start];
+            // The default `{{`/`}}` path is the only one with a backslash-escape
+            // grammar (`\{{`, `\\`), so it's the only one that needs to run
+            // `unescape_html` here; `scan::parse_scan` passes its HTML straight
+            // through `Block::new`/`Block::nameless` untouched.
+            let tag_start = lex.span().start;
+            let mut html = unescape_html(&lex.source()[last..tag_start]);
             self.capacity_hint += html.len();
 
             // Morphing the lexer to match the closing
@@ -102,25 +135,140 @@ impl<'tpl> Template<'tpl> {
             if !matches!(Some(Closing::Ident), _tok) {
                 return Err(Error::UnclosedTag);
             }
-            let mut name = closing.slice();
-                    
+            let mut name: &str = closing.slice();
+
+            // `{{=<% %>=}}` — an inline delimiter switch. `logos` tokens are
+            // fixed at compile time, so once the pair can change mid-template
+            // we hand the remainder off to the hand-rolled `scan` parser
+            // (see `scan::parse_scan_from`), which already knows how to parse
+            // this tag and carry on with the new pair; the still-open
+            // section stack comes along so closing tags across the switch
+            // still match up.
+            if tag == Tag::Escaped && name.starts_with('=') {
+                if !html.is_empty() {
+                    self.blocks.push(Block::nameless(html, Tag::Comment));
+                }
+
+                return scan::parse_scan_from(self, source, tag_start, stack, "{{", "}}", partials);
+            }
+
             match tag {
                 Tag::Escaped | Tag::Unescaped => {
+                    // `{{field:default text}}` — a default may contain
+                    // spaces, so once the name contains a `:` the rest of
+                    // it is captured as a raw slice up to the closing
+                    // braces rather than token by token, which would stop
+                    // at the first space and mis-parse the remainder as a
+                    // nested section. A trailing ` | transform` chain still
+                    // applies to whichever value ends up rendered (the
+                    // field's, or this default), so it's split off the same
+                    // way the plain transform branch below splits one: on
+                    // the first top-level `|`. Without this,
+                    // `{{title:x | upcase}}` would capture `"x | upcase"` as
+                    // a literal default and silently drop the transform.
+                    if let Some(colon) = name.find(':') {
+                        let field = &name[..colon];
+                        let default_start = closing.span().start + colon + 1;
+                        let rest = &source[default_start..];
+                        let close_braces = closing.extras as usize;
+                        let end =
+                            find_tag_end(rest, close_braces, false).ok_or(Error::UnclosedTag)?;
+
+                        let mut specs = split_chain(rest[..end].trim()).into_iter();
+                        let default = specs.next().unwrap_or("").trim();
+
+                        let mut transforms = ArrayVec::<Transform, 4>::new();
+                        for spec in specs.map(str::trim) {
+                            transforms
+                                .try_push(Transform::parse(spec)?)
+                                .map_err(|_| Error::TooManyTransforms)?;
+                        }
+
+                        let mut block = Block::new(html, field, tag);
+                        block.default = Some(default);
+                        block.transforms = transforms.into_iter().collect();
+                        self.blocks.push(block);
+
+                        last = default_start + end + close_braces;
+                        lex = Opening::lexer(source);
+                        lex.bump(last);
+                        continue;
+                    }
+
+                    let mut transforms = ArrayVec::<Transform, 4>::new();
+                    let mut next = closing.next();
+
+                    // `{{field ? a:"A" | b:"B" | default:"C"}}` — the rest
+                    // of the tag up to the closing braces is a choice spec,
+                    // not a transform chain or nested section.
+                    if matches!(next, Some(Closing::Question)) {
+                        let rest = closing.remainder();
+                        let close_braces = closing.extras as usize;
+                        let end =
+                            find_tag_end(rest, close_braces, true).ok_or(Error::UnclosedTag)?;
+
+                        self.blocks.push(Block::new(html, name, Tag::Choice));
+                        self.blocks[tail_idx].choice = Some(Choice::parse(&rest[..end])?);
+
+                        last = closing.span().end + end + close_braces;
+                        lex = Opening::lexer(source);
+                        lex.bump(last);
+                        continue;
+                    }
+
+                    // `{{field | transform | transform}}` — once we hit the
+                    // first `|`, the rest of the tag up to the closing braces
+                    // is captured as a raw slice and split on top-level `|`s
+                    // (ones that aren't part of a regex alternation inside a
+                    // `/pattern/replacement/flags` spec, e.g.
+                    // `/cat|dog/pet/g`) rather than token by token: a regex
+                    // spec like `/a{2,3}/x/g` also contains characters (a
+                    // space, a `}`) the `Ident` token is forbidden from
+                    // including.
+                    if matches!(next, Some(Closing::Pipe)) {
+                        let rest = closing.remainder();
+                        let close_braces = closing.extras as usize;
+                        let end =
+                            find_tag_end(rest, close_braces, false).ok_or(Error::UnclosedTag)?;
+
+                        for spec in split_chain(&rest[..end]).into_iter().map(str::trim) {
+                            transforms
+                                .try_push(Transform::parse(spec)?)
+                                .map_err(|_| Error::TooManyTransforms)?;
+                        }
+
+                        let (name, default) = split_default(name);
+                        let mut block = Block::new(html, name, tag);
+                        block.transforms = transforms.into_iter().collect();
+                        block.default = default;
+                        self.blocks.push(block);
+
+                        last = closing.span().end + end + close_braces;
+                        lex = Opening::lexer(source);
+                        lex.bump(last);
+                        continue;
+                    }
+
                     loop {
-                        match closing.next() {
+                        match next {
                             Some(Closing::Ident) => {
                                 self.blocks.push(Block::new(html, name, Tag::Section));
                                 name = closing.slice();
-                                html = "";
+                                html = Cow::Borrowed("");
                             },
                             Some(Closing::Match) => {
-                                self.blocks.push(Block::new(html, name, tag));
+                                let (name, default) = split_default(name);
+                                let mut block = Block::new(html, name, tag);
+                                block.transforms = transforms.into_iter().collect();
+                                block.default = default;
+                                self.blocks.push(block);
                                 break;
                             }
                             _ => return Err(Error::UnclosedTag),
                         }
+                        next = closing.next();
                     }
-                    
+
                     let d = self.blocks.len() - tail_idx - 1;
                     for i in 0..d {
                         self.blocks[tail_idx + i].children = (d - i) as u32;
@@ -133,7 +281,7 @@ impl<'tpl> Template<'tpl> {
                                 stack.try_push(self.blocks.len())?;
                                 self.blocks.push(Block::new(html, name, Tag::Section));
                                 name = closing.slice();
-                                html = "";
+                                html = Cow::Borrowed("");
                             },
                             Some(Closing::Match) => {
                                 stack.try_push(self.blocks.len())?;
@@ -208,3 +356,187 @@ impl<'tpl> Template<'tpl> {
         Ok(last)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoPartials;
+
+    impl<'tpl> Partials<'tpl> for NoPartials {
+        fn get_partial(&mut self, name: &str) -> Result<&Template<'tpl>, Error> {
+            Err(Error::PartialNotFound(name.into()))
+        }
+    }
+
+    fn parse(source: &str) -> Template<'_> {
+        Template::new(source, &mut NoPartials).unwrap()
+    }
+
+    #[test]
+    fn transform_chain_is_captured_in_order() {
+        let tpl = parse("{{name | upcase | downcase}}");
+
+        assert_eq!(tpl.blocks.len(), 1);
+        assert_eq!(tpl.blocks[0].name, "name");
+        assert_eq!(tpl.blocks[0].transforms.len(), 2);
+        assert!(matches!(tpl.blocks[0].transforms[0], Transform::Upcase));
+        assert!(matches!(tpl.blocks[0].transforms[1], Transform::Downcase));
+    }
+
+    #[test]
+    fn transform_spec_with_space_and_brace_is_not_truncated() {
+        // `{2,3}` would previously cut the spec short at the space and the
+        // `}`, both of which the `Ident` token excludes.
+        let tpl = parse(r"{{name | /a{2,3}/x/g}}");
+
+        assert_eq!(tpl.blocks[0].transforms.len(), 1);
+    }
+
+    #[test]
+    fn triple_brace_transform_closes_on_three_braces() {
+        // Previously stopped at the first `}}`, leaving a stray `}` as
+        // leading HTML on the next block.
+        let tpl = parse(r"{{{name | upcase}}} between {{other}}");
+
+        assert_eq!(tpl.blocks.len(), 2);
+        assert_eq!(tpl.blocks[0].transforms.len(), 1);
+        assert_eq!(tpl.blocks[1].html, " between ");
+        assert_eq!(tpl.blocks[1].name, "other");
+    }
+
+    #[test]
+    fn more_than_four_chained_transforms_is_rejected() {
+        let source = "{{name | upcase | downcase | capitalize | upcase | downcase}}";
+
+        match Template::new(source, &mut NoPartials) {
+            Err(Error::TooManyTransforms) => {}
+            other => panic!("expected Error::TooManyTransforms, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn regex_alternation_in_transform_is_not_split_on_its_pipe() {
+        // The `|` in `cat|dog` is a regex alternation, not a chain
+        // separator; previously the naive `split('|')` cut the pattern in
+        // half and failed to parse either piece as a transform.
+        let tpl = parse(r"{{x | /cat|dog/pet/g}}");
+
+        assert_eq!(tpl.blocks[0].transforms.len(), 1);
+    }
+
+    #[test]
+    fn transform_chain_after_regex_alternation_still_splits() {
+        let tpl = parse(r"{{x | /cat|dog/pet/g | upcase}}");
+
+        assert_eq!(tpl.blocks[0].transforms.len(), 2);
+    }
+
+    #[test]
+    fn transform_with_odd_quote_count_is_not_mistaken_for_unclosed_tag() {
+        // A replace transform may legitimately contain an odd number of `"`,
+        // e.g. escaping a literal quote to `&quot;`. The quote-toggling in
+        // `find_tag_end` is only meaningful for choice-arm literals, so the
+        // transform branch must not trip over it and report `UnclosedTag`.
+        let tpl = parse(r#"{{x | /"/&quot;/g}}"#);
+
+        assert_eq!(tpl.blocks[0].transforms.len(), 1);
+    }
+
+    #[test]
+    fn simple_default() {
+        let tpl = parse("{{name:Anonymous}}");
+
+        assert_eq!(tpl.blocks[0].name, "name");
+        assert_eq!(tpl.blocks[0].default, Some("Anonymous"));
+    }
+
+    #[test]
+    fn default_with_trailing_transform_chain_applies_both() {
+        // The transform chain previously got swallowed into the default
+        // text verbatim instead of being parsed and applied.
+        let tpl = parse("{{title:untitled | upcase}}");
+
+        assert_eq!(tpl.blocks[0].name, "title");
+        assert_eq!(tpl.blocks[0].default, Some("untitled"));
+        assert_eq!(tpl.blocks[0].transforms.len(), 1);
+    }
+
+    #[test]
+    fn default_with_spaces_is_captured_in_full() {
+        // Previously mis-parsed as a nested section `title:Not` followed by
+        // a field `Found`, since the name is otherwise tokenized by a
+        // whitespace-splitting `Ident` regex.
+        let tpl = parse("{{title:Not Found}}");
+
+        assert_eq!(tpl.blocks.len(), 1);
+        assert_eq!(tpl.blocks[0].name, "title");
+        assert_eq!(tpl.blocks[0].default, Some("Not Found"));
+    }
+
+    #[test]
+    fn triple_brace_default_closes_on_three_braces() {
+        // Previously stopped at the first `}}`, leaving a stray `}` as
+        // leading HTML on the next block.
+        let tpl = parse(r"{{{title:Untitled}}} between {{other}}");
+
+        assert_eq!(tpl.blocks.len(), 2);
+        assert_eq!(tpl.blocks[0].default, Some("Untitled"));
+        assert_eq!(tpl.blocks[1].html, " between ");
+        assert_eq!(tpl.blocks[1].name, "other");
+    }
+
+    #[test]
+    fn choice_block_arms_and_default() {
+        let tpl = parse(r#"{{status ? ok:"OK" | err:"Error" | default:"Unknown"}}"#);
+
+        assert_eq!(tpl.blocks[0].name, "status");
+        let choice = tpl.blocks[0].choice.as_ref().expect("choice spec");
+        assert_eq!(choice.arms, vec![("ok", "OK"), ("err", "Error")]);
+        assert_eq!(choice.default, Some("Unknown"));
+    }
+
+    #[test]
+    fn choice_arm_with_quoted_pipe_is_not_split() {
+        // The `|` inside the quoted literal previously broke arm splitting.
+        let tpl = parse(r#"{{kind ? open:"a|b" | closed:"c"}}"#);
+
+        let choice = tpl.blocks[0].choice.as_ref().expect("choice spec");
+        assert_eq!(choice.arms, vec![("open", "a|b"), ("closed", "c")]);
+    }
+
+    #[test]
+    fn triple_brace_choice_closes_on_three_braces() {
+        // Previously stopped at the first `}}`, leaving a stray `}` as
+        // leading HTML on the next block.
+        let tpl = parse(r#"{{{status ? a:"A"}}} between {{other}}"#);
+
+        assert_eq!(tpl.blocks.len(), 2);
+        let choice = tpl.blocks[0].choice.as_ref().expect("choice spec");
+        assert_eq!(choice.arms, vec![("a", "A")]);
+        assert_eq!(tpl.blocks[1].html, " between ");
+        assert_eq!(tpl.blocks[1].name, "other");
+    }
+
+    #[test]
+    fn escaped_delimiter_is_kept_as_literal_text() {
+        let tpl = parse(r"\{{name}} is literal, but {{name}} is a tag");
+
+        assert_eq!(tpl.blocks.len(), 1);
+        assert_eq!(tpl.blocks[0].html, "{{name}} is literal, but ");
+        assert_eq!(tpl.blocks[0].name, "name");
+    }
+
+    #[test]
+    fn escaped_backslash_before_a_tag_does_not_swallow_it() {
+        // The second `\` here previously got mistaken for the start of its
+        // own `\{{` escape, consuming the tag along with it. `\\` should
+        // resolve to a single literal backslash and leave `{{name}}` to open
+        // as an active tag.
+        let tpl = parse(r"\\{{name}}");
+
+        assert_eq!(tpl.blocks.len(), 1);
+        assert_eq!(tpl.blocks[0].html, r"\");
+        assert_eq!(tpl.blocks[0].name, "name");
+    }
+}
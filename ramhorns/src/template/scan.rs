@@ -0,0 +1,257 @@
+// Ramhorns  Copyright (C) 2019  Maciej Hirsz
+//
+// This file is part of Ramhorns. This program comes with ABSOLUTELY NO WARRANTY;
+// This is free software, and you are welcome to redistribute it under the
+// conditions of the GNU General Public License version 3.0.
+//
+// You should have received a copy of the GNU General Public License
+// along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
+
+use arrayvec::ArrayVec;
+
+use super::{hash_name, split_chain, split_default, Block, Choice, Error, Tag, Template, Transform};
+use crate::Partials;
+
+/// Hand-rolled fallback parser used whenever the active delimiter pair isn't
+/// the default `{{`/`}}`. `logos` tokens are fixed at compile time, so a
+/// runtime-chosen pair (set via [`Template::with_delimiters`] or a
+/// `{{=<% %>=}}` tag) can't be expressed as a `Logos` lexer; this walks the
+/// source by hand instead, producing the same kinds of `Block`s the
+/// `logos`-based [`super::parse`] does for the default pair.
+///
+/// A tag's body is taken whole: space-separated names (`{{a b}}`,
+/// `{{#a b}}`, `{{/a b}}`) are read as one name rather than as nested
+/// sections, and backslash escapes (`\{{`, `\\`) in literal text are
+/// passed through unchanged.
+///
+/// Triple-brace unescaping (`{{{name}}}`) is a feature of the default pair
+/// only: under a custom pair there is no "extra brace" to look for, so it is
+/// not recognised here.
+pub(crate) fn parse_scan<'tpl>(
+    tpl: &mut Template<'tpl>,
+    source: &'tpl str,
+    open: &'tpl str,
+    close: &'tpl str,
+    partials: &mut impl Partials<'tpl>,
+) -> Result<usize, Error> {
+    parse_scan_from(tpl, source, 0, ArrayVec::new(), open, close, partials)
+}
+
+/// Continue scanning from a given byte offset and open-section stack,
+/// inheriting whatever delimiter pair is currently active. Used by
+/// [`super::parse`] to hand off mid-template once it hits an inline
+/// `{{=<% %>=}}` tag the `logos` lexer can't express, without losing track
+/// of sections that were already opened on the fast path.
+pub(crate) fn parse_scan_from<'tpl>(
+    tpl: &mut Template<'tpl>,
+    source: &'tpl str,
+    mut last: usize,
+    mut stack: ArrayVec<usize, 16>,
+    mut open: &'tpl str,
+    mut close: &'tpl str,
+    partials: &mut impl Partials<'tpl>,
+) -> Result<usize, Error> {
+    while let Some(idx) = source[last..].find(open) {
+        let start = last + idx;
+
+        let html = &source[last..start];
+        tpl.capacity_hint += html.len();
+
+        let after_open = start + open.len();
+
+        // `{{=<% %>=}}` — switch the active delimiter pair and move on;
+        // this produces no tag of its own, but the literal `html` in front
+        // of it still needs to go somewhere, same as the fast-path handoff
+        // in `super::parse` does for its own switch tag.
+        if source[after_open..].starts_with('=') {
+            let marker = format!("={}", close);
+            let end = source[after_open..]
+                .find(marker.as_str())
+                .map(|idx| after_open + idx)
+                .ok_or(Error::UnclosedTag)?;
+
+            let mut delims = source[after_open + 1..end].split_whitespace();
+            open = delims.next().ok_or(Error::UnclosedTag)?;
+            close = delims.next().ok_or(Error::UnclosedTag)?;
+
+            if !html.is_empty() {
+                tpl.blocks.push(Block::nameless(html, Tag::Comment));
+            }
+
+            // `marker` (built from the *old* `close`) is what we actually
+            // skipped past; `close` above has already been reassigned to the
+            // *new* pair, so advancing by its length here would be off by
+            // however much the two delimiters differ in size.
+            last = end + marker.len();
+            continue;
+        }
+
+        let (tag, name_start) = match source.as_bytes().get(after_open) {
+            Some(b'&') => (Tag::Unescaped, after_open + 1),
+            Some(b'#') => (Tag::Section, after_open + 1),
+            Some(b'^') => (Tag::Inverse, after_open + 1),
+            Some(b'/') => (Tag::Closing, after_open + 1),
+            Some(b'>') => (Tag::Partial, after_open + 1),
+            Some(b'!') => (Tag::Comment, after_open + 1),
+            _ => (Tag::Escaped, after_open),
+        };
+
+        let end = source[name_start..]
+            .find(close)
+            .map(|idx| name_start + idx)
+            .ok_or(Error::UnclosedTag)?;
+
+        let body = source[name_start..end].trim();
+        last = end + close.len();
+
+        match tag {
+            Tag::Escaped | Tag::Unescaped if is_choice(body) => {
+                // `field ? a:"A" | b:"B" | default:"C"`
+                let (name, spec) = body.split_once('?').expect("checked by is_choice");
+
+                let mut block = Block::new(html, name.trim(), Tag::Choice);
+                block.choice = Some(Choice::parse(spec.trim())?);
+                tpl.blocks.push(block);
+            }
+            Tag::Escaped | Tag::Unescaped => {
+                // `field | transform | transform`; the spec after a pipe
+                // covers everything up to the next pipe (or the end), same
+                // as the default-delimiter fast path — split on top-level
+                // `|`s only, so a regex alternation like `/cat|dog/pet/g`
+                // isn't cut in half.
+                let mut parts = split_chain(body).into_iter().map(str::trim);
+                let name = parts.next().unwrap_or("");
+                let (name, default) = split_default(name);
+
+                let mut block = Block::new(html, name, tag);
+                block.default = default;
+
+                for spec in parts {
+                    // Same 4-transform cap as the default-delimiter fast
+                    // path's `ArrayVec<Transform, 4>`.
+                    if block.transforms.len() >= 4 {
+                        return Err(Error::TooManyTransforms);
+                    }
+                    block.transforms.push(Transform::parse(spec)?);
+                }
+
+                tpl.blocks.push(block);
+            }
+            Tag::Section | Tag::Inverse => {
+                stack.try_push(tpl.blocks.len())?;
+                tpl.blocks.push(Block::new(html, body, tag));
+            }
+            Tag::Closing => {
+                let tail_idx = tpl.blocks.len();
+                tpl.blocks.push(Block::nameless(html, Tag::Closing));
+
+                let hash = hash_name(body);
+                let head_idx = stack
+                    .pop()
+                    .ok_or_else(|| Error::UnopenedSection(body.into()))?;
+                let head = &mut tpl.blocks[head_idx];
+                head.children = (tail_idx - head_idx) as u32;
+
+                if head.hash != hash {
+                    return Err(Error::UnclosedSection(head.name.into()));
+                }
+            }
+            Tag::Partial => {
+                tpl.blocks.push(Block::nameless(html, tag));
+                let partial = partials.get_partial(body)?;
+                tpl.blocks.extend_from_slice(&partial.blocks);
+                tpl.capacity_hint += partial.capacity_hint;
+            }
+            Tag::Comment => {
+                tpl.blocks.push(Block::nameless(html, tag));
+            }
+            // Never produced by the sigil classification above; `Tag::Choice`
+            // blocks are only ever built by the `is_choice` arm.
+            Tag::Choice => unreachable!(),
+        }
+    }
+
+    Ok(last)
+}
+
+/// Whether `body` is a choice tag (`field ? a:"A" | ...`) rather than a
+/// transform chain or plain field: the field name is its own
+/// whitespace-delimited token, and it's a choice only if what follows it
+/// starts with `?`, not `|` — so a `?` inside a regex transform spec
+/// (`/a?/b/`) doesn't get misread as the choice sigil. Unlike the
+/// `logos`-based fast path, the `?` need not be followed by a space.
+fn is_choice(body: &str) -> bool {
+    body.split_once(char::is_whitespace)
+        .map(|(_, rest)| rest.trim_start().starts_with('?'))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoPartials;
+
+    impl<'tpl> Partials<'tpl> for NoPartials {
+        fn get_partial(&mut self, name: &str) -> Result<&Template<'tpl>, Error> {
+            Err(Error::PartialNotFound(name.into()))
+        }
+    }
+
+    #[test]
+    fn custom_delimiter_pair_parses_a_field() {
+        let tpl = Template::with_delimiters("<% name %>", "<%", "%>", &mut NoPartials).unwrap();
+
+        assert_eq!(tpl.blocks.len(), 1);
+        assert_eq!(tpl.blocks[0].name, "name");
+    }
+
+    #[test]
+    fn inline_switch_mid_template_crosses_an_open_section() {
+        // The `{{#section}}` opens under the default pair; the inline
+        // `{{=<% %>=}}` tag then switches to `<%`/`%>` before the section is
+        // closed, so `parse_scan_from` must carry the still-open section
+        // stack across the switch for `<%/section%>` to match it up.
+        let tpl = Template::new(
+            "{{#section}}a{{=<% %>=}}<% b %><%/section%>",
+            &mut NoPartials,
+        )
+        .unwrap();
+
+        assert_eq!(tpl.blocks.len(), 4);
+        assert_eq!(tpl.blocks[0].name, "section");
+        assert_eq!(tpl.blocks[0].tag, Tag::Section);
+        assert_eq!(tpl.blocks[0].children, 3);
+        assert_eq!(tpl.blocks[1].html, "a");
+        assert_eq!(tpl.blocks[2].name, "b");
+        assert_eq!(tpl.blocks[3].tag, Tag::Closing);
+    }
+
+    #[test]
+    fn inline_switch_to_a_shorter_open_than_close() {
+        // `open` (`<`, 1 byte) and `close` (`>>>`, 3 bytes) differ in
+        // length; advancing past the switch tag itself by the wrong one's
+        // length previously left the scan off by however much they differ.
+        let tpl = Template::new("before {{=< >>>=}}<name>>>", &mut NoPartials).unwrap();
+
+        assert_eq!(tpl.blocks.len(), 2);
+        assert_eq!(tpl.blocks[0].html, "before ");
+        assert_eq!(tpl.blocks[1].name, "name");
+        assert_eq!(tpl.blocks[1].html, "");
+    }
+
+    #[test]
+    fn literal_text_before_an_in_scan_switch_is_kept() {
+        // The inline switch tag itself produces no block, but the literal
+        // text in front of it previously vanished: it was folded into
+        // `capacity_hint` and then never pushed anywhere.
+        let tpl = Template::with_delimiters("lit<%=[[ ]]=%>[[ x ]]", "<%", "%>", &mut NoPartials)
+            .unwrap();
+
+        assert_eq!(tpl.blocks.len(), 2);
+        assert_eq!(tpl.blocks[0].html, "lit");
+        assert_eq!(tpl.blocks[0].tag, Tag::Comment);
+        assert_eq!(tpl.blocks[1].name, "x");
+        assert_eq!(tpl.blocks[1].html, "");
+    }
+}
@@ -0,0 +1,212 @@
+// Ramhorns  Copyright (C) 2019  Maciej Hirsz
+//
+// This file is part of Ramhorns. This program comes with ABSOLUTELY NO WARRANTY;
+// This is free software, and you are welcome to redistribute it under the
+// conditions of the GNU General Public License version 3.0.
+//
+// You should have received a copy of the GNU General Public License
+// along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
+
+use regex::Regex;
+
+use crate::Error;
+
+/// A value transformation applied to a field before it is written out, e.g.
+/// `{{name | upcase}}` or `{{slug | /(\s+)/-/g}}`.
+#[derive(Debug, Clone)]
+pub(crate) enum Transform {
+    /// `upcase` — uppercase the whole rendered value.
+    Upcase,
+    /// `downcase` — lowercase the whole rendered value.
+    Downcase,
+    /// `capitalize` — uppercase just the first character.
+    Capitalize,
+    /// `/pattern/replacement/flags` — regex search and replace.
+    Replace {
+        pattern: Regex,
+        replacement: String,
+        global: bool,
+    },
+}
+
+/// Split a `upcase | /cat|dog/pet/g` transform chain on top-level `|`s —
+/// ones that don't fall inside a `/pattern/replacement/flags` regex spec, so
+/// an alternation in the pattern (`/cat|dog/pet/g`) isn't cut in half. A
+/// regex spec is recognised by a `/` that opens a token; once inside one, a
+/// `|` is only a chain separator again after the spec's third (closing)
+/// unescaped `/`.
+pub(crate) fn split_chain(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut at_token_start = true;
+    let mut in_regex = false;
+    let mut slash_count = 0;
+    let mut chars = s.char_indices();
+
+    while let Some((idx, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+            at_token_start = false;
+            continue;
+        }
+
+        if c.is_whitespace() && at_token_start {
+            continue;
+        }
+
+        if c == '/' {
+            if at_token_start {
+                in_regex = true;
+                slash_count = 1;
+            } else if in_regex {
+                slash_count += 1;
+                if slash_count == 3 {
+                    in_regex = false;
+                }
+            }
+            at_token_start = false;
+            continue;
+        }
+
+        if c == '|' && !in_regex {
+            parts.push(&s[start..idx]);
+            start = idx + 1;
+            at_token_start = true;
+            in_regex = false;
+            slash_count = 0;
+            continue;
+        }
+
+        at_token_start = false;
+    }
+    parts.push(&s[start..]);
+
+    parts
+}
+
+impl Transform {
+    /// Parse the spec that follows a `|` inside a tag, e.g. `upcase` or
+    /// `/(\s+)/-/g`.
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        match spec {
+            "upcase" => Ok(Transform::Upcase),
+            "downcase" => Ok(Transform::Downcase),
+            "capitalize" => Ok(Transform::Capitalize),
+            _ if spec.starts_with('/') => Self::parse_replace(spec),
+            _ => Err(Error::UnknownTransform(spec.into())),
+        }
+    }
+
+    fn parse_replace(spec: &str) -> Result<Self, Error> {
+        // `/pattern/replacement/flags`, slashes inside `pattern` or
+        // `replacement` may be escaped as `\/`.
+        let mut parts = Vec::with_capacity(3);
+        let mut current = String::new();
+        let mut chars = spec[1..].chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&'/') => {
+                    current.push('/');
+                    chars.next();
+                }
+                '/' => {
+                    parts.push(std::mem::take(&mut current));
+                }
+                c => current.push(c),
+            }
+        }
+        parts.push(current);
+
+        if parts.len() != 3 {
+            return Err(Error::UnknownTransform(spec.into()));
+        }
+
+        let flags = &parts[2];
+        let pattern = if flags.contains('i') {
+            format!("(?i){}", parts[0])
+        } else {
+            parts[0].clone()
+        };
+
+        let pattern = Regex::new(&pattern).map_err(|_| Error::UnknownTransform(spec.into()))?;
+
+        Ok(Transform::Replace {
+            pattern,
+            replacement: parts[1].clone(),
+            global: flags.contains('g'),
+        })
+    }
+
+    /// Apply this transform to `value`, returning the transformed string.
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Transform::Upcase => value.to_uppercase(),
+            Transform::Downcase => value.to_lowercase(),
+            Transform::Capitalize => {
+                let mut chars = value.chars();
+                match chars.next() {
+                    Some(first) => first.to_uppercase().chain(chars).collect(),
+                    None => String::new(),
+                }
+            }
+            Transform::Replace {
+                pattern,
+                replacement,
+                global,
+            } => {
+                let replacement = expand_replacement(replacement, pattern, value);
+
+                if *global {
+                    pattern.replace_all(value, replacement.as_str()).into_owned()
+                } else {
+                    pattern.replace(value, replacement.as_str()).into_owned()
+                }
+            }
+        }
+    }
+}
+
+/// Expand `${1:+matched:unmatched}` conditional captures in a replacement
+/// template before handing it to `regex`'s own `$1`-style expansion.
+fn expand_replacement(replacement: &str, pattern: &Regex, value: &str) -> String {
+    let captures = pattern.captures(value);
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' || chars.peek() != Some(&'{') {
+            out.push(c);
+            continue;
+        }
+
+        chars.next(); // consume '{'
+        let mut token = String::new();
+        for c in chars.by_ref() {
+            if c == '}' {
+                break;
+            }
+            token.push(c);
+        }
+
+        match token.split_once(":+") {
+            Some((group, rest)) => {
+                let matched = captures
+                    .as_ref()
+                    .and_then(|c| c.get(group.parse().unwrap_or(0)))
+                    .is_some();
+
+                let (if_matched, if_unmatched) = rest.split_once(':').unwrap_or((rest, ""));
+
+                out.push_str(if matched { if_matched } else { if_unmatched });
+            }
+            None => {
+                out.push_str("${");
+                out.push_str(&token);
+                out.push('}');
+            }
+        }
+    }
+
+    out
+}
@@ -0,0 +1,16 @@
+// Ramhorns  Copyright (C) 2019  Maciej Hirsz
+//
+// This file is part of Ramhorns. This program comes with ABSOLUTELY NO WARRANTY;
+// This is free software, and you are welcome to redistribute it under the
+// conditions of the GNU General Public License version 3.0.
+//
+// You should have received a copy of the GNU General Public License
+// along with Ramhorns.  If not, see <http://www.gnu.org/licenses/>
+
+use crate::{Error, Template};
+
+/// A source of named partial templates, used while parsing `{{> partial}}` tags.
+pub trait Partials<'tpl> {
+    /// Look up (and lazily parse, if needed) the partial template with the given name.
+    fn get_partial(&mut self, name: &str) -> Result<&Template<'tpl>, Error>;
+}